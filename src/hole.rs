@@ -3,60 +3,321 @@ use core::mem::{self, size_of};
 
 use super::align_up;
 
+/// Low bit of a boundary tag: set if the block it describes is currently allocated. Every block
+/// size passed to `make_tag` must already be even (in practice, word-aligned via
+/// `tagged_block_size`) or this bit would alias a live bit of the size instead of just the flag.
+const TAG_ALLOCATED: usize = 1;
+
+fn make_tag(size: usize, allocated: bool) -> usize {
+    debug_assert!(size & TAG_ALLOCATED == 0, "block size must be even, the flag bit aliases it otherwise");
+    size | if allocated { TAG_ALLOCATED } else { 0 }
+}
+
+fn tag_size(tag: usize) -> usize {
+    tag & !TAG_ALLOCATED
+}
+
+fn tag_allocated(tag: usize) -> bool {
+    tag & TAG_ALLOCATED != 0
+}
+
+unsafe fn write_tag(addr: usize, tag: usize) {
+    *(addr as *mut usize) = tag;
+}
+
+unsafe fn read_tag(addr: usize) -> usize {
+    *(addr as *const usize)
+}
+
+/// Writes matching header and footer tags around a block of `block_size` bytes starting at
+/// `block_addr`, so a neighbor can later read this block's size and allocated-ness directly.
+/// `block_size` must be even (see `TAG_ALLOCATED`).
+unsafe fn write_boundary_tags(block_addr: usize, block_size: usize, allocated: bool) {
+    let tag = make_tag(block_size, allocated);
+    write_tag(block_addr, tag);
+    write_tag(block_addr + block_size - size_of::<usize>(), tag);
+}
+
+/// Rounds `size` up to the next multiple of `align` (a power of two), or `None` if doing so
+/// would overflow `usize`.
+fn checked_align_up(size: usize, align: usize) -> Option<usize> {
+    size.checked_add(align - 1).map(|sum| sum & !(align - 1))
+}
+
+/// The physical size of the block a boundary-tagged allocation of `required_size` usable bytes
+/// occupies: one header word, one footer word, and padding up to a word boundary so the tagged
+/// size is always even (see `TAG_ALLOCATED`) regardless of whether `required_size` itself is.
+fn tagged_block_size(required_size: usize) -> Result<usize, AllocError> {
+    let word = size_of::<usize>();
+    let raw = required_size.checked_add(2 * word).ok_or(AllocError::Overflow)?;
+    checked_align_up(raw, word).ok_or(AllocError::Overflow)
+}
+
+/// The strategy `HoleList` uses to pick a hole for a given allocation request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitPolicy {
+    /// Use the first hole that's big enough. Cheapest search, but tends to leave small
+    /// leftover holes near the front of the list over time.
+    FirstFit,
+    /// Scan every hole and use the one that wastes the least space (front padding + back
+    /// padding) once split. More scanning per allocation, less fragmentation.
+    BestFit,
+    /// Like first-fit, but resumes scanning from the hole after the one used by the previous
+    /// allocation instead of always starting at the front, wrapping around once if needed.
+    NextFit,
+}
+
 pub struct HoleList {
     first: Hole, // dummy
+    bottom: usize,
+    top: usize,
+    policy: FitPolicy,
+    /// For `FitPolicy::NextFit`: address of the free hole to resume scanning from. `None`
+    /// means "start from the front", which is also what happens if the hole this used to
+    /// point at was since allocated or coalesced away.
+    rover: Option<usize>,
 }
 
 impl HoleList {
     pub const fn empty() -> HoleList {
+        HoleList::empty_with_policy(FitPolicy::FirstFit)
+    }
+
+    pub const fn empty_with_policy(policy: FitPolicy) -> HoleList {
         HoleList {
             first: Hole {
                 size: 0,
+                prev: None,
                 next: None,
             },
+            bottom: usize::max_value(),
+            top: 0,
+            policy: policy,
+            rover: None,
         }
     }
 
     pub unsafe fn new(ptr: *mut Hole, size: usize) -> HoleList {
-        assert!(size_of::<Hole>() == Self::min_size());
+        assert!(size_of::<Hole>() <= Self::min_size());
 
-        mem::forget(mem::replace(&mut *ptr,
-                                 Hole {
-                                     size: size,
-                                     next: None,
-                                 }));
+        let mut list = HoleList::empty();
+        list.add_region(ptr, size);
+        list
+    }
 
-        HoleList {
-            first: Hole {
-                size: 0,
-                next: Some(Unique::new(ptr)),
-            },
+    /// Adds another free region to the list, for use when the backing memory is made up of
+    /// several disjoint spans (e.g. several ranges from a memory map) rather than one
+    /// contiguous heap. The region is threaded into the free list and can be served from just
+    /// like any other hole.
+    ///
+    /// If `ptr`/`size` happen to physically abut an already-free hole, the two are merged into
+    /// one contiguous hole. This can't be done by blindly reading the boundary tag just outside
+    /// `[ptr, ptr + size)`, since that memory isn't necessarily ours to read; instead this looks
+    /// up the neighboring address in the free list's own bookkeeping (which we already trust)
+    /// before deciding to merge.
+    ///
+    /// `deallocate`'s neighbor lookups read one word before and after the block being freed, so
+    /// a region's first and last words are reserved as permanently-allocated sentinel tags
+    /// (zero-size, so they can never be mistaken for a real free block) rather than handed out
+    /// as usable memory; this keeps those reads inside memory the caller actually gave us, even
+    /// for a block that starts or ends exactly at the region's edge. Merging with a neighbor
+    /// reuses its sentinel words as ordinary interior bytes of the combined region instead of
+    /// writing fresh ones there.
+    pub unsafe fn add_region(&mut self, ptr: *mut Hole, size: usize) {
+        let word = size_of::<usize>();
+        // every boundary-tagged size must be word-aligned (see `TAG_ALLOCATED`); trim off any
+        // trailing bytes that don't fit a whole word rather than leaving the region's tags
+        // carrying an odd size
+        let size = size & !(word - 1);
+        assert!(size >= Self::min_size() + 2 * word);
+
+        let mut addr = ptr as usize;
+        let mut size = size;
+
+        if let Some((prev_addr, prev_size)) = self.free_hole_ending_at(addr) {
+            self.take_hole(prev_addr);
+            size += prev_size + 2 * word;
+            addr = prev_addr - word;
+        }
+        if let Some((next_addr, next_size)) = self.free_hole_starting_at(addr + size) {
+            self.take_hole(next_addr);
+            size += next_size + 2 * word;
         }
+
+        write_tag(addr, make_tag(0, true));
+        write_tag(addr + size - word, make_tag(0, true));
+
+        let region_addr = addr + word;
+        let region_size = size - 2 * word;
+        write_boundary_tags(region_addr, region_size, false);
+        insert_free_hole(&mut self.first, region_addr, region_size);
+
+        self.bottom = core::cmp::min(self.bottom, region_addr);
+        self.top = core::cmp::max(self.top, region_addr + region_size);
     }
 
-    pub fn allocate_first_fit(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        assert!(size >= Self::min_size());
+    /// If some free hole's own region (including its sentinel words) ends exactly at `addr`,
+    /// returns that hole's `(addr, size)` as tracked in the free list.
+    fn free_hole_ending_at(&self, addr: usize) -> Option<(usize, usize)> {
+        let word = size_of::<usize>();
+        self.holes()
+            .find(|hole| node_addr(hole) + hole.size + word == addr)
+            .map(|hole| (node_addr(hole), hole.size))
+    }
 
-        allocate_first_fit(&mut self.first, size, align).map(|allocation| {
-            if let Some(padding) = allocation.front_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+    /// If some free hole's own region (including its sentinel words) starts exactly at `addr`,
+    /// returns that hole's `(addr, size)` as tracked in the free list.
+    fn free_hole_starting_at(&self, addr: usize) -> Option<(usize, usize)> {
+        let word = size_of::<usize>();
+        self.holes()
+            .find(|hole| node_addr(hole) - word == addr)
+            .map(|hole| (node_addr(hole), hole.size))
+    }
+
+    /// The lowest address managed by any region added so far, or 0 if none has been added yet
+    /// (in which case `end()` is also 0, giving the empty span `[0, 0)` rather than an inverted
+    /// one).
+    pub fn start(&self) -> usize {
+        if self.top == 0 { 0 } else { self.bottom }
+    }
+
+    /// The address one past the last usable byte of the highest region added so far (the
+    /// region's trailing sentinel word is not counted).
+    pub fn end(&self) -> usize {
+        self.top
+    }
+
+    fn holes(&self) -> HoleIter {
+        hole_iter(&self.first)
+    }
+
+    /// Total number of free bytes, summed across every hole in the free list.
+    pub fn free_bytes(&self) -> usize {
+        self.holes().map(|hole| hole.size).fold(0, |a, b| a + b)
+    }
+
+    /// Size of the largest single free hole, or 0 if the list is empty.
+    pub fn largest_free_block(&self) -> usize {
+        self.holes().map(|hole| hole.size).fold(0, core::cmp::max)
+    }
+
+    /// Number of free holes currently in the list.
+    pub fn hole_count(&self) -> usize {
+        self.holes().count()
+    }
+
+    /// Walks the free list once and reports `free_bytes`, `largest_free_block` and
+    /// `hole_count` together, which is cheaper than calling all three separately.
+    pub fn stats(&self) -> HoleStats {
+        let mut stats = HoleStats {
+            free_bytes: 0,
+            largest_free_block: 0,
+            hole_count: 0,
+        };
+        for hole in self.holes() {
+            stats.free_bytes += hole.size;
+            stats.largest_free_block = core::cmp::max(stats.largest_free_block, hole.size);
+            stats.hole_count += 1;
+        }
+        stats
+    }
+
+    /// Removes the free hole at `addr`, clearing `rover` first if it was pointing at it.
+    fn take_hole(&mut self, addr: usize) {
+        if self.rover == Some(addr) {
+            self.rover = None;
+        }
+        unsafe { remove_free_hole(&mut self.first, addr) };
+    }
+
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, AllocError> {
+        let block_size = tagged_block_size(size)?;
+        assert!(block_size >= Self::min_size());
+
+        let found = match self.policy {
+            FitPolicy::FirstFit => find_first_fit(&self.first, size, align)?,
+            FitPolicy::BestFit => find_best_fit(&self.first, size, align)?,
+            FitPolicy::NextFit => {
+                let (found, rover) = find_next_fit(&self.first, size, align, self.rover)?;
+                self.rover = rover;
+                found
             }
-            if let Some(padding) = allocation.back_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+        };
+
+        match found {
+            Some((addr, allocation)) => {
+                self.take_hole(addr);
+
+                if let Some(padding) = allocation.front_padding {
+                    unsafe { write_boundary_tags(padding.addr, padding.size, false) };
+                    deallocate(&mut self.first, padding.addr, padding.size);
+                }
+                if let Some(padding) = allocation.back_padding {
+                    unsafe { write_boundary_tags(padding.addr, padding.size, false) };
+                    deallocate(&mut self.first, padding.addr, padding.size);
+                }
+
+                let block_addr = allocation.info.addr - size_of::<usize>();
+                let block_size = tagged_block_size(allocation.info.size)?;
+                unsafe { write_boundary_tags(block_addr, block_size, true) };
+
+                Ok(allocation.info.addr as *mut u8)
             }
-            allocation.info.addr as *mut u8
-        })
+            None => Err(AllocError::OutOfMemory),
+        }
     }
 
-    pub fn deallocate(&mut self, ptr: *mut u8, size: usize) {
-        println!("deallocate {:p} ({} bytes)", ptr, size);
-        assert!(size >= Self::min_size());
+    /// Frees the allocation given by `(addr, size)`, finding its neighbors via boundary tags
+    /// instead of walking the free list.
+    ///
+    /// Returns `Err(AllocError::UnalignedAddress)` if `ptr` can't be a tagged block (it isn't
+    /// word-aligned), and `Err(AllocError::Overlap)` if the block's own header tag says it's
+    /// already free, or its recorded size disagrees with `size` — either way, a double free or
+    /// a `ptr`/`size` pair that doesn't match a live allocation.
+    pub fn deallocate(&mut self, ptr: *mut u8, size: usize) -> Result<(), AllocError> {
+        let word = size_of::<usize>();
+        let mut block_size = tagged_block_size(size)?;
+        assert!(block_size >= Self::min_size());
+
+        if ptr as usize % word != 0 {
+            return Err(AllocError::UnalignedAddress);
+        }
+
+        let mut block_addr = ptr as usize - word;
 
-        deallocate(&mut self.first, ptr as usize, size)
+        let block_tag = unsafe { read_tag(block_addr) };
+        if !tag_allocated(block_tag) || tag_size(block_tag) != block_size {
+            return Err(AllocError::Overlap);
+        }
+
+        // merge with the preceding block if its footer says it's free
+        let prev_footer_addr = block_addr - word;
+        let prev_tag = unsafe { read_tag(prev_footer_addr) };
+        if !tag_allocated(prev_tag) {
+            let prev_size = tag_size(prev_tag);
+            let prev_addr = block_addr - prev_size;
+            self.take_hole(prev_addr);
+            block_addr = prev_addr;
+            block_size += prev_size;
+        }
+
+        // merge with the following block if its header says it's free
+        let next_header_addr = block_addr + block_size;
+        let next_tag = unsafe { read_tag(next_header_addr) };
+        if !tag_allocated(next_tag) {
+            let next_size = tag_size(next_tag);
+            self.take_hole(next_header_addr);
+            block_size += next_size;
+        }
+
+        unsafe { write_boundary_tags(block_addr, block_size, false) };
+
+        insert_free_hole(&mut self.first, block_addr, block_size);
+        Ok(())
     }
 
     pub fn min_size() -> usize {
-        size_of::<usize>() * 2
+        size_of::<usize>() * 4
     }
 
     #[cfg(test)]
@@ -71,6 +332,7 @@ impl HoleList {
 
 pub struct Hole {
     pub size: usize,
+    prev: Option<Unique<Hole>>,
     pub next: Option<Unique<Hole>>,
 }
 
@@ -81,11 +343,6 @@ impl Hole {
             size: self.size,
         }
     }
-
-    /// Returns a reference to the next hole. Panics if this is the last hole.
-    fn next_unwrap(&mut self) -> &mut Hole {
-        unsafe { self.next.as_mut().unwrap().get_mut() }
-    }
 }
 
 /// Basic information about a hole.
@@ -95,20 +352,77 @@ struct HoleInfo {
     size: usize,
 }
 
-/// The result returned by `split_hole` and `allocate_first_fit`. Contains the address and size of
-/// the allocation (in the `info` field), and the front and back padding.
+/// Error returned by the fallible `HoleList` operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// Computing the end address of a block or hole would wrap around `usize::max_value()`.
+    Overflow,
+    /// No free hole was big enough (or aligned enough) to satisfy the request.
+    OutOfMemory,
+    /// The block being freed overlaps an already-free hole; most likely a double free or a
+    /// pointer/size pair that doesn't match a live allocation.
+    Overlap,
+    /// `ptr` passed to `deallocate` isn't aligned to `size_of::<usize>()`, so it can't be the
+    /// start of a tagged block.
+    UnalignedAddress,
+}
+
+/// Combined result of `HoleList::stats`.
+#[derive(Debug, Clone, Copy)]
+pub struct HoleStats {
+    pub free_bytes: usize,
+    pub largest_free_block: usize,
+    pub hole_count: usize,
+}
+
+/// Read-only walk over the free list, in whatever order the holes happen to be linked in.
+struct HoleIter<'a> {
+    current: Option<&'a Hole>,
+}
+
+impl<'a> Iterator for HoleIter<'a> {
+    type Item = &'a Hole;
+
+    fn next(&mut self) -> Option<&'a Hole> {
+        let hole = self.current.take();
+        if let Some(hole) = hole {
+            self.current = hole.next.as_ref().map(|next| unsafe { next.get() });
+        }
+        hole
+    }
+}
+
+/// The result returned by `split_hole`. Contains the address and size of the allocation (in the
+/// `info` field), and the front and back padding.
 struct Allocation {
     info: HoleInfo,
     front_padding: Option<HoleInfo>,
     back_padding: Option<HoleInfo>,
 }
 
-fn split_hole(hole: HoleInfo, required_size: usize, required_align: usize) -> Option<Allocation> {
+/// Splits `hole` to carve out `required_size` usable bytes aligned to `required_align`,
+/// reserving one boundary-tag word immediately before and after the carved-out block. The
+/// returned `Allocation::info` still describes the usable span only (`required_size` bytes,
+/// starting just past the header), matching the size the caller asked for and will later pass
+/// back to `deallocate`.
+fn split_hole(hole: HoleInfo,
+              required_size: usize,
+              required_align: usize)
+              -> Result<Option<Allocation>, AllocError> {
+    let word = size_of::<usize>();
+    let block_size = tagged_block_size(required_size)?;
+
     let aligned_hole = {
-        let aligned_hole_addr = align_up(hole.addr, required_align);
-        if aligned_hole_addr + required_size > hole.addr + hole.size {
+        // the header occupies the first word of the block, so align the usable address that
+        // follows it, then step back a word to find where the block itself must start
+        let usable_addr = align_up(hole.addr.checked_add(word).ok_or(AllocError::Overflow)?,
+                                    required_align);
+        let aligned_hole_addr = usable_addr - word;
+        let required_end = aligned_hole_addr.checked_add(block_size).ok_or(AllocError::Overflow)?;
+        let hole_end = hole.addr.checked_add(hole.size).ok_or(AllocError::Overflow)?;
+        if required_end > hole_end {
             // hole is too small
-            return None;
+            return Ok(None);
         }
         HoleInfo {
             addr: aligned_hole_addr,
@@ -121,7 +435,7 @@ fn split_hole(hole: HoleInfo, required_size: usize, required_align: usize) -> Op
         None
     } else if aligned_hole.addr < hole.addr + HoleList::min_size() {
         // we can't use this hole because the required padding would create a new, too small hole
-        return None;
+        return Ok(None);
     } else {
         // the required alignment causes some padding before the allocation
         Some(HoleInfo {
@@ -130,116 +444,438 @@ fn split_hole(hole: HoleInfo, required_size: usize, required_align: usize) -> Op
         })
     };
 
-    let back_padding = if aligned_hole.size == required_size {
+    let back_padding = if aligned_hole.size == block_size {
         // the aligned hole has exactly the size that's needed, no padding accrues
         None
-    } else if aligned_hole.size - required_size < HoleList::min_size() {
+    } else if aligned_hole.size - block_size < HoleList::min_size() {
         // we can't use this hole since its remains would form a new, too small hole
-        return None;
+        return Ok(None);
     } else {
         // the hole is bigger than necessary, so there is some padding behind the allocation
         Some(HoleInfo {
-            addr: aligned_hole.addr + required_size,
-            size: aligned_hole.size - required_size,
+            addr: aligned_hole.addr + block_size,
+            size: aligned_hole.size - block_size,
         })
     };
 
-    Some(Allocation {
+    Ok(Some(Allocation {
         info: HoleInfo {
-            addr: aligned_hole.addr,
+            addr: aligned_hole.addr + word,
             size: required_size,
         },
         front_padding: front_padding,
         back_padding: back_padding,
-    })
-}
-
-fn allocate_first_fit(previous: &mut Hole, size: usize, align: usize) -> Option<Allocation> {
-    previous.next
-            .as_mut()
-            .and_then(|current| split_hole(unsafe { current.get() }.info(), size, align))
-            .map(|allocation| {
-                // hole is big enough, so remove it from the list by updating the previous pointer
-                previous.next = previous.next_unwrap().next.take();
-                allocation
-            })
-            .or_else(|| {
-                // hole is too small, try next hole
-                allocate_first_fit(previous.next_unwrap(), size, align)
-            })
-}
-
-/// Frees the allocation given by `(addr, size)`. It starts at the given hole and walks the list to
-/// find the correct place (the list is sorted by address). 
-fn deallocate(hole: &mut Hole, addr: usize, size: usize) {
-    assert!(size >= HoleList::min_size());
-
-    let hole_addr = if hole.size == 0 {
-        // It's the dummy hole, which is the head of the HoleList. It's somewhere on the stack,
-        // so it's address is not the address of the hole. We set the addr to 0 as it's always
-        // the first hole.
+    }))
+}
+
+fn hole_iter(first: &Hole) -> HoleIter {
+    HoleIter { current: first.next.as_ref().map(|hole| unsafe { hole.get() }) }
+}
+
+fn node_addr(hole: &Hole) -> usize {
+    hole as *const _ as usize
+}
+
+/// Scans holes in iteration order and returns the first one `split_hole` accepts. Holes that are
+/// too small (or too small once aligned) are skipped; an `Overflow` error from `split_hole` ends
+/// the scan and is returned as is.
+fn find_first_fit(first: &Hole,
+                   size: usize,
+                   align: usize)
+                   -> Result<Option<(usize, Allocation)>, AllocError> {
+    for hole in hole_iter(first) {
+        if let Some(allocation) = split_hole(hole.info(), size, align)? {
+            return Ok(Some((node_addr(hole), allocation)));
+        }
+    }
+    Ok(None)
+}
+
+/// Scans every hole and keeps the one whose split wastes the fewest bytes (front padding +
+/// back padding), reducing fragmentation at the cost of a full O(n) scan per allocation.
+fn find_best_fit(first: &Hole,
+                  size: usize,
+                  align: usize)
+                  -> Result<Option<(usize, Allocation)>, AllocError> {
+    let mut best: Option<(usize, usize, Allocation)> = None; // (waste, addr, allocation)
+
+    for hole in hole_iter(first) {
+        if let Some(allocation) = split_hole(hole.info(), size, align)? {
+            let waste = allocation.front_padding.map_or(0, |p| p.size) +
+                        allocation.back_padding.map_or(0, |p| p.size);
+            let is_better = match best {
+                Some((best_waste, _, _)) => waste < best_waste,
+                None => true,
+            };
+            if is_better {
+                best = Some((waste, node_addr(hole), allocation));
+            }
+        }
+    }
+
+    Ok(best.map(|(_, addr, allocation)| (addr, allocation)))
+}
+
+/// Scans the free list for the first hole `split_hole` accepts, starting at `rover` (if it
+/// still names a hole in the list) and wrapping around to the front once if needed. Returns
+/// the match (if any) together with the rover value to resume from next time.
+fn find_next_fit(first: &Hole,
+                  size: usize,
+                  align: usize,
+                  rover: Option<usize>)
+                  -> Result<(Option<(usize, Allocation)>, Option<usize>), AllocError> {
+    // skip forward to the rover's hole without consuming it, so it's the first hole the
+    // scan below actually tests; if it's no longer in the list (allocated or coalesced
+    // away since), this falls through and we just scan from the front instead
+    let mut skipping = rover.is_some();
+    for hole in hole_iter(first) {
+        if skipping {
+            if node_addr(hole) == rover.unwrap() {
+                skipping = false;
+            } else {
+                continue;
+            }
+        }
+
+        if let Some(allocation) = split_hole(hole.info(), size, align)? {
+            let next_rover = hole.next.as_ref().map(|next| unsafe { next.get() }).map(node_addr);
+            return Ok((Some((node_addr(hole), allocation)), next_rover));
+        }
+    }
+
+    if rover.is_some() {
+        // wrap around: the rover hole (or everything after it) didn't fit, retry from the front
+        for hole in hole_iter(first) {
+            if let Some(allocation) = split_hole(hole.info(), size, align)? {
+                let next_rover = hole.next.as_ref().map(|next| unsafe { next.get() }).map(node_addr);
+                return Ok((Some((node_addr(hole), allocation)), next_rover));
+            }
+        }
+    }
+
+    Ok((None, rover))
+}
+
+/// Inserts a freed region as a new hole at the front of the free list. Coalescing with
+/// neighbors has already happened (via boundary tags) by the time this is called, so the list
+/// no longer needs to stay sorted by address; pushing to the front keeps insertion O(1).
+fn insert_free_hole(first: &mut Hole, addr: usize, size: usize) {
+    let new_hole_ptr = addr as *mut Hole;
+    let old_head = first.next.take();
+    if let Some(mut head) = old_head {
+        unsafe { head.get_mut().prev = Some(Unique::new(new_hole_ptr)) };
+    }
+    let new_hole = Hole {
+        size: size,
+        prev: None,
+        next: old_head,
+    };
+    mem::forget(mem::replace(unsafe { &mut *new_hole_ptr }, new_hole));
+    first.next = Some(unsafe { Unique::new(new_hole_ptr) });
+}
+
+/// Removes the free hole living at `addr` from the free list, using its own `prev`/`next` links
+/// rather than walking from the list head.
+unsafe fn remove_free_hole(first: &mut Hole, addr: usize) {
+    let mut node = Unique::new(addr as *mut Hole);
+    let (prev, next) = {
+        let node = node.get_mut();
+        (node.prev.take(), node.next.take())
+    };
+
+    match prev {
+        Some(mut prev) => prev.get_mut().next = next,
+        None => first.next = next,
+    }
+    if let Some(mut next) = next {
+        next.get_mut().prev = prev;
+    }
+}
+
+fn deallocate(first: &mut Hole, addr: usize, size: usize) {
+    insert_free_hole(first, addr, size)
+}
+
+/// Number of second-level subdivisions per first-level size class, expressed as a log2 so that
+/// the second-level bitmap fits exactly in a `u32`.
+const SL_INDEX_COUNT_LOG2: usize = 5;
+/// Number of second-level subdivisions (`2^SL_INDEX_COUNT_LOG2`).
+const SL_INDEX_COUNT: usize = 1 << SL_INDEX_COUNT_LOG2;
+/// Number of first-level size classes: one bit of `fl_bitmap` per bit of `usize`.
+const FL_INDEX_COUNT: usize = size_of::<usize>() * 8;
+
+/// Splits `size` into a first-level class `fl = floor(log2(size))` and a second-level class `sl`
+/// that linearly subdivides the `[2^fl, 2^(fl+1))` range into `SL_INDEX_COUNT` buckets.
+fn mapping_insert(size: usize) -> (usize, usize) {
+    let fl = FL_INDEX_COUNT - 1 - (size.leading_zeros() as usize);
+    let sl = if fl < SL_INDEX_COUNT_LOG2 {
         0
     } else {
-        // tt's a real hole in memory and its address is the address of the hole
-        hole as *mut _ as usize
+        (size >> (fl - SL_INDEX_COUNT_LOG2)) & (SL_INDEX_COUNT - 1)
     };
+    (fl, sl)
+}
 
-    // Each freed block must be handled by the previous hole in memory. Thus the freed address must
-    // be always behind the current hole.
-    assert!(hole_addr + hole.size <= addr);
+/// Rounds `size` up to the next class boundary so that looking up `mapping_insert(rounded)`
+/// is guaranteed to find a hole big enough to satisfy a request of `size` bytes.
+fn mapping_search(size: usize) -> (usize, usize) {
+    let fl = FL_INDEX_COUNT - 1 - (size.leading_zeros() as usize);
+    let rounded = if fl < SL_INDEX_COUNT_LOG2 {
+        size
+    } else {
+        let granularity = 1usize << (fl - SL_INDEX_COUNT_LOG2);
+        (size + granularity - 1) & !(granularity - 1)
+    };
+    mapping_insert(rounded)
+}
 
-    // get information about the next block
-    let next_hole_info = hole.next.as_ref().map(|next| unsafe { next.get().info() });
+/// A free block tracked by `Tlsf`. Unlike `Hole`, which sits in the single free list of a
+/// `HoleList`, `TlsfHole` sits in one of the doubly-linked, size-classed free lists and carries
+/// its own `prev` link so it can be unlinked directly.
+struct TlsfHole {
+    size: usize,
+    prev: Option<Unique<TlsfHole>>,
+    next: Option<Unique<TlsfHole>>,
+}
 
-    match next_hole_info {
-        Some(next) if hole_addr + hole.size == addr && addr + size == next.addr => {
-            // block fills the gap between this hole and the next hole
-            // before:  ___XXX____YYYYY____    where X is this hole and Y the next hole
-            // after:   ___XXXFFFFYYYYY____    where F is the freed block
+impl TlsfHole {
+    fn info(&self) -> HoleInfo {
+        HoleInfo {
+            addr: self as *const _ as usize,
+            size: self.size,
+        }
+    }
+}
 
-            hole.size += size + next.size; // merge the F and Y blocks to this X block
-            hole.next = hole.next_unwrap().next.take(); // remove the Y block
+/// A two-level segregated-fit (TLSF) free-space index over the memory handed to it with
+/// `add_region`. Instead of walking a linear chain like `HoleList`, holes are indexed by
+/// size class, so both `allocate` and `deallocate` are O(1): a first-level bitmap says which
+/// power-of-two ranges have any free memory, a per-`fl` second-level bitmap says which linear
+/// subrange within that range to use, and `heads[fl][sl]` is the free list for that class.
+pub struct Tlsf {
+    fl_bitmap: usize,
+    sl_bitmap: [u32; FL_INDEX_COUNT],
+    heads: [[Option<Unique<TlsfHole>>; SL_INDEX_COUNT]; FL_INDEX_COUNT],
+}
+
+impl Tlsf {
+    pub fn empty() -> Tlsf {
+        Tlsf {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_INDEX_COUNT],
+            heads: [[None; SL_INDEX_COUNT]; FL_INDEX_COUNT],
         }
-        Some(_) if hole_addr + hole.size == addr => {
-            // block is right behind this hole but there is used memory after it
-            // before:  ___XXX______YYYYY____    where X is this hole and Y the next hole
-            // after:   ___XXXFFFF__YYYYY____    where F is the freed block
+    }
 
-            hole.size += size; // merge the F block to this X block
+    /// Adds a free region to the index. `size` must be at least `HoleList::min_size()`.
+    pub unsafe fn add_region(&mut self, ptr: *mut u8, size: usize) {
+        assert!(size >= HoleList::min_size());
+        self.insert(ptr as usize, size);
+    }
+
+    fn insert(&mut self, addr: usize, size: usize) {
+        let (fl, sl) = mapping_insert(size);
+
+        let node_ptr = addr as *mut TlsfHole;
+        let old_head = self.heads[fl][sl].take();
+        if let Some(mut head) = old_head {
+            unsafe { head.get_mut().prev = Some(Unique::new(node_ptr)) };
         }
-        Some(next) if addr + size == next.addr => {
-            // block is right before the next hole but there is used memory before it
-            // before:  ___XXX______YYYYY____    where X is this hole and Y the next hole
-            // after:   ___XXX__FFFFYYYYY____    where F is the freed block
+        let node = TlsfHole {
+            size: size,
+            prev: None,
+            next: old_head,
+        };
+        mem::forget(mem::replace(unsafe { &mut *node_ptr }, node));
+
+        self.heads[fl][sl] = Some(unsafe { Unique::new(node_ptr) });
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
 
-            hole.next = hole.next_unwrap().next.take(); // remove the Y block
-            deallocate(hole, addr, size + next.size); // free the merged F/Y block
+    /// Unlinks `node` from the `fl`/`sl` free list it lives in, clearing the bitmap bits if that
+    /// was the last hole in its class.
+    fn unlink(&mut self, fl: usize, sl: usize, mut node: Unique<TlsfHole>) {
+        let (prev, next) = unsafe {
+            let node = node.get_mut();
+            (node.prev.take(), node.next.take())
+        };
+
+        match prev {
+            Some(mut prev) => unsafe { prev.get_mut().next = next },
+            None => self.heads[fl][sl] = next,
+        }
+        if let Some(mut next) = next {
+            unsafe { next.get_mut().prev = prev };
         }
-        Some(next) if next.addr <= addr => {
-            // block is behind the next hole, so we delegate it to the next hole
-            // before:  ___XXX__YYYYY________    where X is this hole and Y the next hole
-            // after:   ___XXX__YYYYY__FFFF__    where F is the freed block
 
-            deallocate(hole.next_unwrap(), addr, size);
+        if self.heads[fl][sl].is_none() {
+            self.sl_bitmap[fl] &= !(1 << sl);
+            if self.sl_bitmap[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
         }
-        _ => {
-            // block is between this and the next hole
-            // before:  ___XXX________YYYYY_    where X is this hole and Y the next hole
-            // after:   ___XXX__FFFF__YYYYY_    where F is the freed block
+    }
 
-            // or: this is the last hole
-            // before:  ___XXX_________    where X is this hole
-            // after:   ___XXX__FFFF___    where F is the freed block
+    /// Finds the smallest non-empty class at or above `(fl, sl)`, first by checking for a larger
+    /// `sl` within `fl`, then by checking higher `fl`s (taking their smallest non-empty `sl`).
+    /// `sl` may be `SL_INDEX_COUNT` (one past the last valid second-level class) to mean "skip
+    /// the rest of `fl` entirely and look at higher first-level classes only".
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<(usize, usize)> {
+        let sl_map = if sl < SL_INDEX_COUNT {
+            self.sl_bitmap[fl] & (!0u32 << sl)
+        } else {
+            0
+        };
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
 
-            let new_hole = Hole {
-                size: size,
-                next: hole.next.take(), // the reference to the Y block (if it exists)
+        let fl_map = if fl + 1 < FL_INDEX_COUNT {
+            self.fl_bitmap & (!0usize << (fl + 1))
+        } else {
+            0
+        };
+        if fl_map == 0 {
+            return None;
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    /// Allocates `size` bytes aligned to `align` in O(1) amortized, or `None` if no class holds a
+    /// head hole big enough once alignment and `split_hole`'s header/footer words are accounted
+    /// for. Only the first hole of each class's free list is tried; the holes behind it are not.
+    ///
+    /// `find_suitable` only looks at size, so the class it names isn't guaranteed to fit once
+    /// `required_align` is taken into account (a large alignment can make a hole too small after
+    /// all); when that happens this keeps looking at the next non-empty class instead of
+    /// reporting out-of-memory early.
+    pub fn allocate(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let size = core::cmp::max(size, HoleList::min_size());
+        let (mut fl, mut sl) = mapping_search(size);
+
+        loop {
+            let (cfl, csl) = self.find_suitable(fl, sl)?;
+            let head = self.heads[cfl][csl].unwrap();
+            let info = unsafe { head.get() }.info();
+
+            let allocation = match split_hole(info, size, align) {
+                Ok(Some(allocation)) => allocation,
+                _ => {
+                    fl = cfl;
+                    sl = csl + 1;
+                    continue;
+                }
             };
-            // write the new hole to the freed memory
-            let ptr = addr as *mut Hole;
-            mem::forget(mem::replace(unsafe { &mut *ptr }, new_hole));
-            // add the F block as the next block of the X block
-            hole.next = Some(unsafe { Unique::new(ptr) });
+            self.unlink(cfl, csl, head);
+
+            if let Some(padding) = allocation.front_padding {
+                self.insert(padding.addr, padding.size);
+            }
+            if let Some(padding) = allocation.back_padding {
+                self.insert(padding.addr, padding.size);
+            }
+            return Some(allocation.info.addr as *mut u8);
         }
     }
-}
\ No newline at end of file
+
+    /// Returns a previously allocated block to the index in O(1). Unlike `HoleList::deallocate`,
+    /// this does not coalesce with neighboring holes, since the size-classed free lists carry no
+    /// address ordering to find them by.
+    ///
+    /// `allocate` hands back `info.addr`, which sits one header word into the block that
+    /// `split_hole` actually carved out (starting at `info.addr - word` and
+    /// `tagged_block_size(size)` bytes long, with `size` raised to `HoleList::min_size()` as in
+    /// `allocate`); this
+    /// reconstructs that full block so the header and footer words reserved at allocation time
+    /// are returned to the index instead of leaking on every alloc/free cycle.
+    pub fn deallocate(&mut self, ptr: *mut u8, size: usize) {
+        let size = core::cmp::max(size, HoleList::min_size());
+        let word = size_of::<usize>();
+        let block_size = tagged_block_size(size).expect("size was already accepted by allocate");
+        self.insert(ptr as usize - word, block_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_list_has_zero_span() {
+        let list = HoleList::empty();
+        assert_eq!(list.start(), 0);
+        assert_eq!(list.end(), 0);
+    }
+
+    #[test]
+    fn allocate_overflowing_size_returns_err() {
+        let mut list = HoleList::empty();
+        assert_eq!(list.allocate(usize::max_value() - 4, 8),
+                   Err(AllocError::Overflow));
+    }
+
+    #[test]
+    fn deallocate_wrong_size_is_rejected() {
+        let mut heap = [0u8; 256];
+        let mut list = unsafe { HoleList::new(heap.as_mut_ptr() as *mut Hole, heap.len()) };
+
+        let ptr = list.allocate(32, 1).unwrap();
+        assert_eq!(list.deallocate(ptr, 16), Err(AllocError::Overlap));
+    }
+
+    #[test]
+    fn free_whole_region_does_not_read_outside_region() {
+        let mut heap = [0u8; 256];
+        let mut list = unsafe { HoleList::new(heap.as_mut_ptr() as *mut Hole, heap.len()) };
+
+        let word = size_of::<usize>();
+        let size = heap.len() - 4 * word; // whole region, no front/back padding
+        let ptr = list.allocate(size, 1).unwrap();
+        assert_eq!(ptr as usize, heap.as_ptr() as usize + 2 * word);
+
+        list.deallocate(ptr, size).unwrap();
+        assert!(list.allocate(size, 1).is_ok());
+    }
+
+    #[test]
+    fn allocate_dealloc_odd_size_roundtrips() {
+        let mut heap = [0u8; 256];
+        let mut list = unsafe { HoleList::new(heap.as_mut_ptr() as *mut Hole, heap.len()) };
+
+        let ptr = list.allocate(17, 1).unwrap();
+        assert_eq!(list.deallocate(ptr, 17), Ok(()));
+    }
+
+    #[test]
+    fn add_region_merges_with_abutting_region() {
+        let word = size_of::<usize>();
+        let mut heap = [0u8; 512];
+        let mid = heap.len() / 2;
+        let mut list = unsafe {
+            HoleList::new(heap.as_mut_ptr() as *mut Hole, mid)
+        };
+        unsafe {
+            list.add_region(heap.as_mut_ptr().add(mid) as *mut Hole, heap.len() - mid)
+        };
+
+        // two separately-added but physically abutting regions should behave as a single
+        // hole spanning the whole buffer (minus the outer sentinel words): an allocation
+        // bigger than either region alone must still fit.
+        let size = heap.len() - 4 * word;
+        assert!(list.allocate(size, 1).is_ok());
+    }
+
+    #[test]
+    fn tlsf_alloc_dealloc_cycle_does_not_leak_overhead() {
+        let mut heap = [0u8; 4096];
+        let mut tlsf = Tlsf::empty();
+        unsafe { tlsf.add_region(heap.as_mut_ptr(), heap.len()) };
+
+        for _ in 0..8 {
+            let ptr = tlsf.allocate(64, 8).expect("heap shouldn't shrink across alloc/dealloc cycles");
+            tlsf.deallocate(ptr, 64);
+        }
+    }
+}